@@ -0,0 +1,42 @@
+use std::fmt;
+use std::io;
+
+/// A non-fatal problem encountered while parsing or selecting PKI objects,
+/// e.g. a malformed PEM block or an ambiguous common name match.
+#[derive(Debug)]
+pub struct ParseError {
+    pub msg: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Everything that can go wrong while scanning for or replacing certificates
+/// and private keys on disk, attributed to the file it happened on so a run
+/// over many files can report every failure instead of aborting on the
+/// first one.
+#[derive(Debug)]
+pub enum CertReplaceError {
+    Read(io::Error),
+    Parse(ParseError),
+    Backup(io::Error),
+    Write(io::Error),
+}
+
+impl fmt::Display for CertReplaceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CertReplaceError::Read(err) => write!(f, "failed to read file: {}", err),
+            CertReplaceError::Parse(err) => write!(f, "failed to parse PEM objects: {}", err),
+            CertReplaceError::Backup(err) => write!(f, "failed to back up file: {}", err),
+            CertReplaceError::Write(err) => write!(f, "failed to write file: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CertReplaceError {}
@@ -0,0 +1,202 @@
+use crate::error::{CertReplaceError, ParseError};
+use crate::model::{Cert, Encoding, San};
+
+use chrono::{DateTime, Duration, Utc};
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::{X509NameRef, X509};
+use rcgen::{CertificateParams, DistinguishedName, DnType, Ia5String, KeyPair, KeyUsagePurpose, SanType};
+use std::convert::TryFrom;
+use std::fs;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A CA certificate and key used to sign freshly generated replacements,
+/// loaded from the files given via `--ca-cert`/`--ca-key`.
+pub struct CaSigner {
+    cert: rcgen::Certificate,
+    key: KeyPair,
+}
+
+impl CaSigner {
+    /// Loads a CA signer from a PEM certificate and a PEM private key.
+    ///
+    /// The CA certificate is parsed with openssl, the same as every other
+    /// certificate in this codebase, rather than through rcgen's own
+    /// `CertificateParams::from_ca_cert_pem` — that path only exists behind
+    /// rcgen's non-default `x509-parser` feature, and would have pulled in a
+    /// second PEM-parsing stack just to read the CA's subject DN. `signed_by`
+    /// only reads the distinguished name, key identifier method and key
+    /// usages off the issuer's `Certificate`, so a self-signed wrapper built
+    /// from the real CA key and the CA's actual subject is all it needs.
+    pub fn load(cert_path: &str, key_path: &str) -> Result<CaSigner, CertReplaceError> {
+        let cert_pem = fs::read_to_string(cert_path).map_err(CertReplaceError::Read)?;
+        let key_pem = fs::read_to_string(key_path).map_err(CertReplaceError::Read)?;
+
+        let key = KeyPair::from_pem(&key_pem).map_err(|err| parse_err("CA private key", err))?;
+        let ca_cert =
+            X509::from_pem(cert_pem.as_bytes()).map_err(|err| parse_err("CA certificate", err))?;
+
+        let mut params = CertificateParams::default();
+        params.distinguished_name = distinguished_name_from_x509(ca_cert.subject_name());
+        let cert = params
+            .self_signed(&key)
+            .map_err(|err| parse_err("CA certificate", err))?;
+
+        Ok(CaSigner { cert, key })
+    }
+}
+
+/// A generated artifact rendered in both PEM and DER, so the caller can
+/// pick whichever encoding the file it is replacing used.
+pub struct Encoded {
+    pem: Vec<u8>,
+    der: Vec<u8>,
+}
+
+impl Encoded {
+    /// The bytes for this artifact in the given encoding.
+    pub fn bytes(&self, encoding: Encoding) -> &[u8] {
+        match encoding {
+            Encoding::Pem => &self.pem,
+            Encoding::Der => &self.der,
+        }
+    }
+}
+
+/// Mints a fresh replacement certificate and private key, copying the
+/// subject common name, SAN list and validity duration of `cert`. Signs
+/// with `ca` if given, otherwise self-signs. Returns the new certificate
+/// and private key, ready to flow through the existing replace path.
+pub fn generate_replacement(
+    cert: &Cert,
+    ca: Option<&CaSigner>,
+) -> Result<(Encoded, Encoded), CertReplaceError> {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = distinguished_name(cert);
+    params.subject_alt_names = cert
+        .sans
+        .iter()
+        .map(san_type)
+        .collect::<Result<Vec<_>, _>>()?;
+    // rcgen can't recover the exact original key usage bits through the
+    // openssl bindings we use elsewhere, so a generated replacement always
+    // gets the key usages a TLS server certificate needs, regardless of what
+    // the original certificate actually had set (e.g. a CA or client-auth
+    // certificate). This is documented on `--generate`'s help text.
+    params.key_usages = vec![
+        KeyUsagePurpose::DigitalSignature,
+        KeyUsagePurpose::KeyEncipherment,
+    ];
+
+    let not_before = Utc::now();
+    let not_after = not_before + validity_duration(cert);
+    params.not_before = to_offset_date_time(not_before)?;
+    params.not_after = to_offset_date_time(not_after)?;
+
+    let key_pair = KeyPair::generate().map_err(|err| parse_err("generated key pair", err))?;
+
+    let generated = match ca {
+        Some(ca) => params
+            .signed_by(&key_pair, &ca.cert, &ca.key)
+            .map_err(|err| parse_err("generated certificate", err))?,
+        None => params
+            .self_signed(&key_pair)
+            .map_err(|err| parse_err("generated certificate", err))?,
+    };
+
+    let cert_encoded = Encoded {
+        pem: generated.pem().into_bytes(),
+        der: generated.der().to_vec(),
+    };
+
+    let key = PKey::private_key_from_der(&key_pair.serialize_der()).map_err(|err| {
+        CertReplaceError::Parse(ParseError {
+            msg: format!("Failed to convert generated key to PKCS8: {}", err),
+        })
+    })?;
+    let key_encoded = Encoded {
+        pem: key.private_key_to_pem_pkcs8().map_err(|err| {
+            CertReplaceError::Parse(ParseError {
+                msg: format!("Failed to serialize generated key: {}", err),
+            })
+        })?,
+        der: key.private_key_to_pkcs8().map_err(|err| {
+            CertReplaceError::Parse(ParseError {
+                msg: format!("Failed to serialize generated key: {}", err),
+            })
+        })?,
+    };
+
+    Ok((cert_encoded, key_encoded))
+}
+
+/// Builds an rcgen distinguished name from the matched certificate's common
+/// name. `Cert` only parses the common name out of the subject, not the
+/// full DN, so other fields (O/OU/C/ST, etc.) are never copied into the
+/// generated replacement; this is documented on `--generate`'s help text.
+fn distinguished_name(cert: &Cert) -> DistinguishedName {
+    let mut dn = DistinguishedName::new();
+    if !cert.common_name.is_empty() {
+        dn.push(DnType::CommonName, cert.common_name.as_str());
+    }
+    dn
+}
+
+/// Builds an rcgen distinguished name from an openssl subject name, copying
+/// over every entry we have an rcgen `DnType` for. Entries with values that
+/// aren't valid UTF-8, or with NIDs we don't map, are skipped rather than
+/// failing the whole load.
+fn distinguished_name_from_x509(name: &X509NameRef) -> DistinguishedName {
+    let mut dn = DistinguishedName::new();
+    for entry in name.entries() {
+        let ty = match entry.object().nid() {
+            Nid::COMMONNAME => DnType::CommonName,
+            Nid::COUNTRYNAME => DnType::CountryName,
+            Nid::LOCALITYNAME => DnType::LocalityName,
+            Nid::STATEORPROVINCENAME => DnType::StateOrProvinceName,
+            Nid::ORGANIZATIONNAME => DnType::OrganizationName,
+            Nid::ORGANIZATIONALUNITNAME => DnType::OrganizationalUnitName,
+            _ => continue,
+        };
+        if let Ok(value) = entry.data().to_string() {
+            dn.push(ty, value.as_str());
+        }
+    }
+    dn
+}
+
+/// Converts one of our parsed SAN entries into the equivalent rcgen type.
+fn san_type(san: &San) -> Result<SanType, CertReplaceError> {
+    match san {
+        San::Dns(name) => Ia5String::try_from(name.as_str())
+            .map(SanType::DnsName)
+            .map_err(|err| parse_err("DNS SAN entry", err)),
+        San::Email(addr) => Ia5String::try_from(addr.as_str())
+            .map(SanType::Rfc822Name)
+            .map_err(|err| parse_err("email SAN entry", err)),
+        San::Ip(ip) => IpAddr::from_str(ip)
+            .map(SanType::IpAddress)
+            .map_err(|err| parse_err("IP SAN entry", err)),
+    }
+}
+
+/// The original certificate's validity window, falling back to a year if
+/// either bound is missing or malformed.
+fn validity_duration(cert: &Cert) -> Duration {
+    match (cert.not_before, cert.not_after) {
+        (Some(not_before), Some(not_after)) if not_after > not_before => not_after - not_before,
+        _ => Duration::days(365),
+    }
+}
+
+fn to_offset_date_time(dt: DateTime<Utc>) -> Result<time::OffsetDateTime, CertReplaceError> {
+    time::OffsetDateTime::from_unix_timestamp(dt.timestamp())
+        .map_err(|err| parse_err("generated certificate validity", err))
+}
+
+fn parse_err(what: &str, err: impl std::fmt::Display) -> CertReplaceError {
+    CertReplaceError::Parse(ParseError {
+        msg: format!("Failed to build {}: {}", what, err),
+    })
+}
@@ -1,13 +1,17 @@
+mod error;
+mod generate;
 mod model;
 mod parse;
 
+use error::{CertReplaceError, ParseError};
+use generate::CaSigner;
 use model::*;
 use parse::*;
 
 use chrono::Utc;
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::{
     io::{self, Write},
     str,
@@ -15,19 +19,68 @@ use std::{
 use structopt::StructOpt;
 
 /// The help text to display for the common name parameter.
-const COMMON_NAME_HELP: &'static str = "Subject common name to match in x509 certificates.";
+const COMMON_NAME_HELP: &str =
+    "Name to match in target certificates: checked against the subject common \
+name as well as DNS, IP and email Subject Alternative Names.";
+
+/// The help text to display for the SAN flag.
+const SAN_HELP: &str =
+    "Restrict matching to Subject Alternative Name entries, ignoring the common name.";
+
+/// The help text to display for the expiring-within parameter.
+const EXPIRING_WITHIN_HELP: &str =
+    "Only match certificates whose expiry (notAfter) falls within this many days of now.";
+
+/// The help text to display for the issuer parameter.
+const ISSUER_HELP: &str = "Only match certificates whose issuer name contains this substring.";
+
+/// The help text to display for the serial parameter.
+const SERIAL_HELP: &str = "Only match the certificate with this hex serial number.";
+
+/// The help text to display for the key-algo parameter.
+const KEY_ALGO_HELP: &str = "Only match certificates with this public key algorithm: 'rsa' or 'ec'.";
+
+/// The help text to display for the generate flag.
+const GENERATE_HELP: &str =
+    "Instead of requiring a replacement certificate file, mint a fresh replacement for each \
+matched certificate with a freshly generated key, copying its subject common name, SAN list and \
+validity duration. Only the common name is copied, not the full subject DN (other fields such as \
+O/OU/C/ST are dropped), and the generated certificate always gets DigitalSignature and \
+KeyEncipherment key usage regardless of the original's actual usage bits. Cannot be used \
+together with --cert.";
+
+/// The help text to display for the CA certificate parameter.
+const CA_CERT_HELP: &str =
+    "Path to a CA certificate to sign generated replacements with, instead of self-signing them. \
+Must be used together with --ca-key.";
+
+/// The help text to display for the CA key parameter.
+const CA_KEY_HELP: &str = "Path to the CA private key matching --ca-cert.";
 
 /// The help text to display for the certificate parameter.
-const CERTIFICATE_HELP: &'static str =
+const CERTIFICATE_HELP: &str =
     "Path to file containing certificate to use as a replacement. \
 If this file contains only one certificate, no common name needs to be provided.
 Will just find matching certs if not provided.";
 
 /// The help text to display for the private key parameter.
-const PRIVATE_KEY_HELP: &'static str =
+const PRIVATE_KEY_HELP: &str =
     "Path to file containing private key to use as a replacement. \
 Private keys will not be replaced if this is not provided.";
 
+/// The help text to display for the dry-run flag.
+const DRY_RUN_HELP: &str =
+    "Print a unified diff of what would change in each file, without writing anything.";
+
+/// The help text to display for the no-backup flag.
+const NO_BACKUP_HELP: &str =
+    "Skip writing a backup copy of a file before overwriting it. Cannot be used with --backup-dir.";
+
+/// The help text to display for the backup-dir parameter.
+const BACKUP_DIR_HELP: &str =
+    "Directory to write backup copies into, instead of alongside the original file. Created if \
+it does not already exist.";
+
 /// Structopt cli struct.
 #[derive(StructOpt)]
 pub struct Cli {
@@ -36,134 +89,288 @@ pub struct Cli {
     /// Common name to match in target certificates.
     #[structopt(short = "n", help = COMMON_NAME_HELP)]
     pub common_name: Option<String>,
+    /// Restrict matching to SAN entries, ignoring the common name.
+    #[structopt(long = "san", help = SAN_HELP)]
+    pub san_only: bool,
+    /// Only match certificates expiring within this many days.
+    #[structopt(long = "expiring-within", help = EXPIRING_WITHIN_HELP)]
+    pub expiring_within: Option<i64>,
+    /// Only match certificates with this issuer substring.
+    #[structopt(long = "issuer", help = ISSUER_HELP)]
+    pub issuer: Option<String>,
+    /// Only match the certificate with this hex serial number.
+    #[structopt(long = "serial", help = SERIAL_HELP)]
+    pub serial: Option<String>,
+    /// Only match certificates with this key algorithm.
+    #[structopt(long = "key-algo", help = KEY_ALGO_HELP)]
+    pub key_algo: Option<KeyAlgo>,
     /// Path to file with x509 certificate to use as replacement.
     #[structopt(long = "cert", help = CERTIFICATE_HELP)]
     pub certificate: Option<String>,
     /// Path to file with private key to use as replacement.
     #[structopt(long = "priv", help = PRIVATE_KEY_HELP)]
     pub private_key: Option<String>,
+    /// Generate a replacement certificate and key instead of using --cert/--priv.
+    #[structopt(long = "generate", help = GENERATE_HELP)]
+    pub generate: bool,
+    /// CA certificate to sign generated replacements with.
+    #[structopt(long = "ca-cert", help = CA_CERT_HELP)]
+    pub ca_cert: Option<String>,
+    /// CA private key matching --ca-cert.
+    #[structopt(long = "ca-key", help = CA_KEY_HELP)]
+    pub ca_key: Option<String>,
+    /// Preview changes as a diff instead of writing them.
+    #[structopt(long = "dry-run", help = DRY_RUN_HELP)]
+    pub dry_run: bool,
+    /// Skip backing up files before they are overwritten.
+    #[structopt(long = "no-backup", help = NO_BACKUP_HELP)]
+    pub no_backup: bool,
+    /// Directory to write backup copies into.
+    #[structopt(long = "backup-dir", help = BACKUP_DIR_HELP)]
+    pub backup_dir: Option<String>,
 }
 
 /// Main loop of the app.
 fn main() {
     let args = Cli::from_args();
 
-    let verb = match &args.certificate {
-        Some(cert_path) => {
-            let cert = choose_cert(cert_path, args.common_name.as_ref()).unwrap();
-            let privkey = match &args.private_key {
-                None => None,
-                Some(privkey_path) => Some(choose_privkey(privkey_path, &cert).unwrap()),
-            };
-            Verb::Replace {
-                cn: cert.common_name.clone(),
-                cert,
-                privkey,
-            }
+    let write_opts = match build_write_options(&args) {
+        Ok(opts) => opts,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
         }
-        None => match args.common_name {
-            None => panic!("No certificate or common name provided."),
-            Some(cn) => Verb::Find { cn },
-        },
     };
 
-    if get_user_consent(&verb) {
-        let paths = find_certs(PathBuf::from(args.path), verb.cn(), verb.privkeys());
-        match verb {
-            Verb::Find { cn: _ } => print_pems(paths),
-            Verb::Replace {
-                cn: _,
-                cert,
-                privkey,
-            } => replace_pems(paths, cert, privkey),
+    let verb = match build_verb(&args) {
+        Ok(verb) => verb,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
         }
-    } else {
-        panic!(
-            "User declined to replace objects for common name: {}",
-            verb.cn()
+    };
+
+    if !write_opts.dry_run && !get_user_consent(&verb) {
+        eprintln!(
+            "User declined to replace objects matching: {}",
+            verb.filter()
         );
+        std::process::exit(1);
     }
-}
 
-/// Chooses a certificate matching a common name from a file of pki objs,
-/// or returns an error if there is no unique match.
-fn choose_cert(path: &str, cn: Option<&String>) -> Result<Cert, ParseError> {
-    let path = PathBuf::from(path);
-    let pkis = parse_pkiobjs(PathBuf::from(path)).unwrap();
-
-    if cn.is_none() {
-        let mut certs = Vec::new();
-        for pki in pkis {
-            if let PKIObject::Cert(cert) = pki {
-                certs.push(cert);
-            }
+    let failures = match verb {
+        Verb::Find { filter } => {
+            let scan = find_certs(PathBuf::from(args.path), &filter, None);
+            print_pems(&scan.locators);
+            scan.failures
         }
-        if certs.len() == 1 {
-            return Ok(certs.pop().unwrap());
-        } else {
-            return Err(ParseError {
-                msg: "Certificate file does not contain exactly one certificate, so a common name must be provided.".to_string() 
-            });
+        Verb::Replace {
+            filter,
+            cert,
+            privkey,
+        } => {
+            let pubkey = cert.cert.public_key().ok();
+            let scan = find_certs(PathBuf::from(args.path), &filter, pubkey);
+            let mut failures = scan.failures;
+            failures.extend(replace_pems(scan.locators, *cert, privkey, &write_opts));
+            failures
         }
-    } else {
-        let cn = cn.unwrap();
-
-        let mut certs = Vec::new();
-        for pki in pkis {
-            match pki {
-                PKIObject::Cert(cert) => {
-                    if &cert.common_name == cn {
-                        certs.push(cert);
-                    }
+        Verb::Generate {
+            filter,
+            ca_cert,
+            ca_key,
+        } => {
+            let ca = match load_ca_signer(ca_cert, ca_key) {
+                Ok(ca) => ca,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
                 }
-                PKIObject::PrivKey(_) => {}
-            }
+            };
+            let scan = find_matching_certs(PathBuf::from(args.path), &filter);
+            let mut failures = scan.failures;
+            failures.extend(generate_pems(scan.certs, ca.as_ref(), &write_opts));
+            failures
+        }
+    };
+
+    print_report(&failures);
+    if !failures.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// How a file should be written once its replacement content has been
+/// computed: whether to preview it as a diff instead of writing, and how
+/// (or whether) to back it up first.
+struct WriteOptions {
+    dry_run: bool,
+    backup: BackupPolicy,
+}
+
+/// How backups are made before a file is overwritten.
+enum BackupPolicy {
+    /// Write a `<file>.<timestamp>.bkp` copy alongside the original.
+    Default,
+    /// Skip backups entirely.
+    Disabled,
+    /// Write backups into the given directory, under a mirror of the
+    /// original file's directory path. Created if it does not already exist.
+    Dir(PathBuf),
+}
+
+/// Builds the write options described by the parsed CLI flags.
+fn build_write_options(args: &Cli) -> Result<WriteOptions, CertReplaceError> {
+    let backup = match (args.no_backup, &args.backup_dir) {
+        (true, Some(_)) => {
+            return Err(CertReplaceError::Parse(ParseError {
+                msg: "--no-backup cannot be used together with --backup-dir.".to_string(),
+            }))
         }
-        if certs.len() == 1 {
-            return Ok(certs.pop().unwrap());
-        } else {
-            return Err(ParseError {
-                msg: format!("Certificate file does not contain exactly one certificate with common name: {}", cn)
-            });
+        (true, None) => BackupPolicy::Disabled,
+        (false, Some(dir)) => BackupPolicy::Dir(PathBuf::from(dir)),
+        (false, None) => BackupPolicy::Default,
+    };
+
+    Ok(WriteOptions {
+        dry_run: args.dry_run,
+        backup,
+    })
+}
+
+/// Builds the certificate filter described by the parsed CLI selectors.
+/// Fails eagerly if `--expiring-within` is out of the range `chrono::Duration`
+/// can represent, rather than letting it panic later inside `matches`.
+fn build_filter(args: &Cli) -> Result<CertFilter, CertReplaceError> {
+    if let Some(days) = args.expiring_within {
+        if chrono::Duration::try_days(days).is_none() {
+            return Err(CertReplaceError::Parse(ParseError {
+                msg: format!(
+                    "--expiring-within {} is out of range; expected a number of days that fits in a valid duration.",
+                    days
+                ),
+            }));
         }
     }
+
+    Ok(CertFilter {
+        name: args.common_name.clone(),
+        san_only: args.san_only,
+        expiring_within: args.expiring_within,
+        issuer: args.issuer.clone(),
+        serial: args.serial.clone(),
+        key_algo: args.key_algo,
+    })
 }
 
-/// Chooses a private key matching a cert from a file of pki objs,
-/// or returns an error if there is no unique match.
-fn choose_privkey(path: &str, cert: &Cert) -> Result<PrivKey, ParseError> {
-    if let Ok(pubkey) = cert.cert.public_key() {
-        let path = PathBuf::from(path);
-        let pkis = parse_pkiobjs(PathBuf::from(path)).unwrap();
-        let mut privkeys = Vec::new();
-
-        for pki in pkis {
-            match pki {
-                PKIObject::PrivKey(pkey) => {
-                    if pkey.key.public_eq(&pubkey) {
-                        privkeys.push(pkey);
-                    }
-                }
-                PKIObject::Cert(_) => {}
+/// Builds the verb to execute from the parsed CLI arguments, choosing the
+/// replacement certificate and private key if one was provided.
+fn build_verb(args: &Cli) -> Result<Verb, CertReplaceError> {
+    let filter = build_filter(args)?;
+
+    if args.generate {
+        if args.certificate.is_some() || args.private_key.is_some() {
+            return Err(CertReplaceError::Parse(ParseError {
+                msg: "--generate cannot be used together with --cert or --priv.".to_string(),
+            }));
+        }
+        if filter.is_empty() {
+            return Err(CertReplaceError::Parse(ParseError {
+                msg: "No selector provided for --generate.".to_string(),
+            }));
+        }
+        return Ok(Verb::Generate {
+            filter,
+            ca_cert: args.ca_cert.clone(),
+            ca_key: args.ca_key.clone(),
+        });
+    }
+
+    match &args.certificate {
+        Some(cert_path) => {
+            let cert = choose_cert(cert_path, &filter)?;
+            let privkey = match &args.private_key {
+                None => None,
+                Some(privkey_path) => Some(choose_privkey(privkey_path, &cert)?),
+            };
+            Ok(Verb::Replace {
+                filter,
+                cert: Box::new(cert),
+                privkey,
+            })
+        }
+        None => {
+            if filter.is_empty() {
+                Err(CertReplaceError::Parse(ParseError {
+                    msg: "No certificate or selector provided.".to_string(),
+                }))
+            } else {
+                Ok(Verb::Find { filter })
             }
         }
-        if privkeys.len() == 1 {
-            return Ok(privkeys.pop().unwrap());
-        } else {
-            return Err(ParseError {
-                msg: format!(
-                "Provided file does not contain exactly one private key match cert with common name: {}",
+    }
+}
+
+/// Chooses a certificate matching `filter` from a file of pki objs, or
+/// returns an error if there is no unique match. If `filter` has no
+/// predicates set, the file must contain exactly one certificate.
+fn choose_cert(path: &str, filter: &CertFilter) -> Result<Cert, CertReplaceError> {
+    let pkis = parse_pkiobjs(PathBuf::from(path))?;
+
+    let mut certs: Vec<Cert> = pkis
+        .into_iter()
+        .filter_map(|pki| match pki {
+            PKIObject::Cert(cert) if filter.is_empty() || filter.matches(&cert) => Some(cert),
+            _ => None,
+        })
+        .collect();
+
+    if certs.len() == 1 {
+        Ok(certs.pop().unwrap())
+    } else if filter.is_empty() {
+        Err(CertReplaceError::Parse(ParseError {
+            msg: "Certificate file does not contain exactly one certificate, so a selector must be provided.".to_string(),
+        }))
+    } else {
+        Err(CertReplaceError::Parse(ParseError {
+            msg: format!(
+                "Certificate file does not contain exactly one certificate matching: {}",
+                filter
+            ),
+        }))
+    }
+}
+
+/// Chooses a private key matching a cert from a file of pki objs,
+/// or returns an error if there is no unique match.
+fn choose_privkey(path: &str, cert: &Cert) -> Result<PrivKey, CertReplaceError> {
+    let pubkey = cert.cert.public_key().map_err(|_| {
+        CertReplaceError::Parse(ParseError {
+            msg: format!(
+                "Failed to get public key from provided certificate, cn: {}",
                 cert.common_name
             ),
-            });
-        }
+        })
+    })?;
+
+    let pkis = parse_pkiobjs(PathBuf::from(path))?;
+    let mut privkeys: Vec<PrivKey> = pkis
+        .into_iter()
+        .filter_map(|pki| match pki {
+            PKIObject::PrivKey(pkey) if pkey.key.public_eq(&pubkey) => Some(pkey),
+            _ => None,
+        })
+        .collect();
+
+    if privkeys.len() == 1 {
+        Ok(privkeys.pop().unwrap())
     } else {
-        return Err(ParseError {
+        Err(CertReplaceError::Parse(ParseError {
             msg: format!(
-                "Failed to get public key from provided certificate, cn: {}",
+                "Provided file does not contain exactly one private key match cert with common name: {}",
                 cert.common_name
             ),
-        });
+        }))
     }
 }
 
@@ -178,25 +385,37 @@ fn get_user_consent(verb: &Verb) -> bool {
     io::stdin()
         .read_line(&mut input)
         .expect("Failed to read user confirmation for target common name.");
-    return input.to_lowercase().starts_with("y");
+    input.to_lowercase().starts_with("y")
 }
 
 /// Prints the locations of pems.
-fn print_pems(pems: Vec<PEMLocator>) {
+fn print_pems(pems: &[PEMLocator]) {
     println!("\nMatching certificates:");
-    for cert in &pems {
+    for cert in pems {
         if cert.kind == PEMKind::Cert {
             println!("\t{:#?}", cert.path);
         }
     }
     println!("\nMatching private keys:");
-    for key in &pems {
+    for key in pems {
         if key.kind == PEMKind::PrivKey {
             println!("\t{:#?}", key.path);
         }
     }
 }
 
+/// Prints a summary of every file that failed to process, if any.
+fn print_report(failures: &[(PathBuf, CertReplaceError)]) {
+    if failures.is_empty() {
+        println!("\nAll matching files processed successfully.");
+        return;
+    }
+    println!("\nFailed to process the following files:");
+    for (path, err) in failures {
+        println!("\t{:?}: {}", path, err);
+    }
+}
+
 /// Maps pems by their file paths.
 fn pems_by_path(pems: Vec<PEMLocator>) -> HashMap<PathBuf, Vec<PEMLocator>> {
     let mut map = HashMap::new();
@@ -206,50 +425,103 @@ fn pems_by_path(pems: Vec<PEMLocator>) -> HashMap<PathBuf, Vec<PEMLocator>> {
         }
         map.get_mut(&pem.path).unwrap().push(pem);
     }
-    return map;
+    map
 }
 
-/// Replaces the target pems with the new data.
-fn replace_pems(targets: Vec<PEMLocator>, cert: Cert, privkey: Option<PrivKey>) {
+/// Replaces the target pems with the new data, continuing past any file
+/// that fails so a single unreadable or unwritable file doesn't abandon the
+/// rest of the run. Every failure is returned attributed to its path.
+fn replace_pems(
+    targets: Vec<PEMLocator>,
+    cert: Cert,
+    privkey: Option<PrivKey>,
+    opts: &WriteOptions,
+) -> Vec<(PathBuf, CertReplaceError)> {
+    let mut failures = Vec::new();
+
     let cert_pem = match cert.cert.to_pem() {
         Ok(pem) => pem,
-        Err(err) => panic!("Failed to convert new certificate to PEM: {:?}", err),
+        Err(err) => {
+            failures.push((
+                cert.locator.path.clone(),
+                CertReplaceError::Parse(ParseError {
+                    msg: format!("Failed to convert new certificate to PEM: {}", err),
+                }),
+            ));
+            return failures;
+        }
+    };
+    let cert_der = match cert.cert.to_der() {
+        Ok(der) => der,
+        Err(err) => {
+            failures.push((
+                cert.locator.path.clone(),
+                CertReplaceError::Parse(ParseError {
+                    msg: format!("Failed to convert new certificate to DER: {}", err),
+                }),
+            ));
+            return failures;
+        }
     };
 
-    let (pkey_pem, pkey_path) = if let Some(privkey) = privkey {
-        match privkey.key.private_key_to_pem_pkcs8() {
-            Ok(pem) => (pem, privkey.locator.path),
-            Err(err) => panic!("Failed to convert new private key to PEM: {:?}", err),
+    let (pkey_pem, pkey_der, pkey_path) = match privkey {
+        None => (vec![], vec![], PathBuf::new()),
+        Some(privkey) => {
+            let pem = match privkey.key.private_key_to_pem_pkcs8() {
+                Ok(pem) => pem,
+                Err(err) => {
+                    failures.push((
+                        privkey.locator.path.clone(),
+                        CertReplaceError::Parse(ParseError {
+                            msg: format!("Failed to convert new private key to PEM: {}", err),
+                        }),
+                    ));
+                    return failures;
+                }
+            };
+            let der = match privkey.key.private_key_to_pkcs8() {
+                Ok(der) => der,
+                Err(err) => {
+                    failures.push((
+                        privkey.locator.path.clone(),
+                        CertReplaceError::Parse(ParseError {
+                            msg: format!("Failed to convert new private key to DER: {}", err),
+                        }),
+                    ));
+                    return failures;
+                }
+            };
+            (pem, der, privkey.locator.path)
         }
-    } else {
-        (vec![], PathBuf::new())
     };
 
     for (path, pems) in pems_by_path(targets) {
         if (path == cert.locator.path) | (path == pkey_path) {
             continue;
         }
-        if let Err(err) = backup_file(&path) {
-            println!("Failed to backup file at {:#?}: {:#?}", path, err);
-            continue;
-        }
-        let mut content = match fs::read(&path) {
+
+        let original = match fs::read(&path) {
+            Ok(bytes) => bytes,
             Err(err) => {
-                println!(
-                    "Failed to read file marked for modification at {:?}: {:?}",
-                    path, err
-                );
-                return;
+                failures.push((path, CertReplaceError::Read(err)));
+                continue;
             }
-            Ok(bytes) => bytes,
         };
+        let mut content = original.clone();
+
+        // A file can hold a mix of PEM and DER locators only if DER, being
+        // unarmored, spans the whole file; either way the first locator's
+        // encoding tells us how to preview this file in dry-run mode.
+        let encoding = pems.first().map_or(Encoding::Pem, |locator| locator.encoding);
 
         // pems always read in order, so offset can be scalar.
         let mut offset: isize = 0;
         for locator in pems {
-            let pem = match locator.kind {
-                PEMKind::Cert => &cert_pem,
-                PEMKind::PrivKey => &pkey_pem,
+            let pem = match (locator.kind, locator.encoding) {
+                (PEMKind::Cert, Encoding::Pem) => &cert_pem,
+                (PEMKind::Cert, Encoding::Der) => &cert_der,
+                (PEMKind::PrivKey, Encoding::Pem) => &pkey_pem,
+                (PEMKind::PrivKey, Encoding::Der) => &pkey_der,
             };
             let (target_start, target_end) = (locator.start as isize, locator.end as isize);
             let (start, end) = (
@@ -260,24 +532,372 @@ fn replace_pems(targets: Vec<PEMLocator>, cert: Cert, privkey: Option<PrivKey>)
             offset += pem.len() as isize - (target_end - target_start);
         }
 
+        if opts.dry_run {
+            print_diff(&path, &original, &content, encoding);
+            continue;
+        }
+
+        if let Err(err) = backup_file(&path, &opts.backup) {
+            failures.push((path, CertReplaceError::Backup(err)));
+            continue;
+        }
+
         println!("Replacing PEMs in {:?}", &path);
-        if let Err(err) = fs::write(path, content) {
-            println!("Error writing: {:?}", err)
-        };
+        if let Err(err) = write_atomic(&path, &content) {
+            failures.push((path, CertReplaceError::Write(err)));
+        }
+    }
+
+    failures
+}
+
+/// Loads a CA signer from `--ca-cert`/`--ca-key`, if both were given. Having
+/// only one of the two is an error rather than silently self-signing.
+fn load_ca_signer(
+    ca_cert: Option<String>,
+    ca_key: Option<String>,
+) -> Result<Option<CaSigner>, CertReplaceError> {
+    match (ca_cert, ca_key) {
+        (Some(cert_path), Some(key_path)) => CaSigner::load(&cert_path, &key_path).map(Some),
+        (None, None) => Ok(None),
+        _ => Err(CertReplaceError::Parse(ParseError {
+            msg: "--ca-cert and --ca-key must be given together.".to_string(),
+        })),
+    }
+}
+
+/// Mints and writes a freshly generated replacement for each matched
+/// certificate, continuing past any failure so a single bad file doesn't
+/// abandon the rest of the run. Each certificate gets its own key, written
+/// to a sibling `<cert file>.key` path; both the certificate file and any
+/// existing sibling key file are backed up before being overwritten.
+///
+/// Certificates are grouped by the file they came from first. Unlike
+/// `replace_pems`, which splices every match in a file against one common
+/// replacement, `--generate` mints a distinct key per certificate — there is
+/// no single cumulative offset that would make sense for two independently
+/// generated replacements in the same file, so a file with more than one
+/// match is rejected with a clear error instead of silently splicing against
+/// stale offsets.
+fn generate_pems(
+    certs: Vec<Cert>,
+    ca: Option<&CaSigner>,
+    opts: &WriteOptions,
+) -> Vec<(PathBuf, CertReplaceError)> {
+    let mut failures = Vec::new();
+
+    let mut by_path: HashMap<PathBuf, Vec<Cert>> = HashMap::new();
+    for cert in certs {
+        by_path.entry(cert.locator.path.clone()).or_default().push(cert);
+    }
+
+    for (path, mut matches) in by_path {
+        if matches.len() > 1 {
+            failures.push((
+                path,
+                CertReplaceError::Parse(ParseError {
+                    msg: format!(
+                        "{} certificates in this file match the selector; --generate only supports \
+one matching certificate per file, since each gets its own freshly generated key.",
+                        matches.len()
+                    ),
+                }),
+            ));
+            continue;
+        }
+
+        let cert = matches.pop().expect("grouped by_path entries are never empty");
+        failures.extend(generate_one(cert, ca, opts));
+    }
+
+    failures
+}
+
+/// Mints and writes a freshly generated replacement for a single matched
+/// certificate. See `generate_pems` for the one-match-per-file constraint
+/// this relies on.
+fn generate_one(
+    cert: Cert,
+    ca: Option<&CaSigner>,
+    opts: &WriteOptions,
+) -> Vec<(PathBuf, CertReplaceError)> {
+    let mut failures = Vec::new();
+
+    let (cert_encoded, key_encoded) = match generate::generate_replacement(&cert, ca) {
+        Ok(encoded) => encoded,
+        Err(err) => {
+            failures.push((cert.locator.path.clone(), err));
+            return failures;
+        }
+    };
+    // The generated key has no file of its own yet, so it is written out in
+    // whichever encoding the certificate it replaces used.
+    let encoding = cert.locator.encoding;
+
+    let path = cert.locator.path.clone();
+    let original = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            failures.push((path, CertReplaceError::Read(err)));
+            return failures;
+        }
+    };
+    let spliced = [
+        &original[..cert.locator.start],
+        cert_encoded.bytes(encoding),
+        &original[cert.locator.end..],
+    ]
+    .concat();
+
+    let key_path = key_path_for(&path);
+    let existing_key = fs::read(&key_path).unwrap_or_default();
+
+    if opts.dry_run {
+        print_diff(&path, &original, &spliced, encoding);
+        print_diff(&key_path, &existing_key, key_encoded.bytes(encoding), encoding);
+        return failures;
+    }
+
+    if let Err(err) = backup_file(&path, &opts.backup) {
+        failures.push((path, CertReplaceError::Backup(err)));
+        return failures;
+    }
+
+    println!("Writing generated certificate to {:?}", &path);
+    if let Err(err) = write_atomic(&path, &spliced) {
+        failures.push((path, CertReplaceError::Write(err)));
+        return failures;
+    }
+
+    if key_path.exists() {
+        if let Err(err) = backup_file(&key_path, &opts.backup) {
+            failures.push((key_path, orphaned_cert_error(&path, CertReplaceError::Backup(err))));
+            return failures;
+        }
+    }
+
+    println!("Writing generated private key to {:?}", &key_path);
+    if let Err(err) = write_atomic(&key_path, key_encoded.bytes(encoding)) {
+        failures.push((key_path, orphaned_cert_error(&path, CertReplaceError::Write(err))));
+    }
+
+    failures
+}
+
+/// Wraps a private-key write/backup failure with a note that the paired
+/// certificate at `cert_path` has already been overwritten. Once this
+/// happens the two are no longer a matching pair, which is worse than an
+/// ordinary failure: the certificate file now has no key on disk that
+/// matches it.
+fn orphaned_cert_error(cert_path: &Path, err: CertReplaceError) -> CertReplaceError {
+    let note = format!(
+        "(note: the certificate at {:?} was already replaced and now has no matching key on disk)",
+        cert_path
+    );
+    match err {
+        CertReplaceError::Backup(io_err) => {
+            CertReplaceError::Backup(io::Error::new(io_err.kind(), format!("{} {}", io_err, note)))
+        }
+        CertReplaceError::Write(io_err) => {
+            CertReplaceError::Write(io::Error::new(io_err.kind(), format!("{} {}", io_err, note)))
+        }
+        other => other,
     }
 }
 
-/// Creates a backup of a file with ".\<timestamp\>.bkp" appended to the filename.
-fn backup_file(path: &PathBuf) -> Result<(), io::Error> {
+/// The sibling path a generated private key is written to: the
+/// certificate's filename with a ".key" extension appended.
+fn key_path_for(cert_path: &Path) -> PathBuf {
+    let mut key_path = cert_path.to_path_buf();
+    let file_name = match key_path.file_name() {
+        Some(name) => format!("{}.key", name.to_string_lossy()),
+        None => "generated.key".to_string(),
+    };
+    key_path.set_file_name(file_name);
+    key_path
+}
+
+/// Creates a backup of a file with ".\<timestamp\>.bkp" appended to the
+/// filename, placed alongside the original or under `policy`'s backup
+/// directory. Does nothing if backups are disabled.
+fn backup_file(path: &Path, policy: &BackupPolicy) -> Result<(), io::Error> {
+    let dir = match policy {
+        BackupPolicy::Disabled => return Ok(()),
+        BackupPolicy::Default => None,
+        BackupPolicy::Dir(dir) => Some(dir.as_path()),
+    };
+
     let ext = match path.extension() {
         None => String::new(),
         Some(os_str) => os_str.to_string_lossy().to_string(),
     };
-    let mut bkp_path = path.clone();
+    let mut bkp_path = match dir {
+        // Mirror the source file's directory structure under the backup
+        // directory instead of just its basename, so files that share a
+        // name but live in different directories (e.g. two `fullchain.pem`
+        // under different vhosts) don't overwrite each other's backups.
+        Some(dir) => dir.join(mirrored_path(path)),
+        None => path.to_path_buf(),
+    };
     bkp_path.set_extension(format!(
         "{ext}.{}.bkp",
         Utc::now().format("%y-%m-%d-T%H-%M")
     ));
+    if let Some(parent) = bkp_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
     fs::copy(path, bkp_path)?;
-    return Ok(());
+    Ok(())
+}
+
+/// Strips `path` down to its normal (non-root, non-`..`) components, so it
+/// can be joined onto a backup directory as a relative path that still
+/// reflects where the original file lived, however `path` itself was
+/// specified (absolute, relative, with `.`/`..` segments, etc).
+fn mirrored_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Writes `content` to `path` atomically: the new data is written to a
+/// temp file in the same directory, then renamed over the target, so an
+/// interrupted write never leaves a half-written file behind.
+fn write_atomic(path: &Path, content: &[u8]) -> Result<(), io::Error> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let file_name = match path.file_name() {
+        Some(name) => format!(".{}.{}.tmp", name.to_string_lossy(), std::process::id()),
+        None => format!(".{}.tmp", std::process::id()),
+    };
+    let tmp_path = dir.join(file_name);
+
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Prints a unified diff between a file's current content and what it would
+/// become, or nothing if the two are identical. DER is binary, so a line
+/// diff of it would be meaningless noise; for `Encoding::Der` this prints a
+/// one-line "changed" notice instead of lossy-decoding the bytes as text.
+fn print_diff(path: &Path, old: &[u8], new: &[u8], encoding: Encoding) {
+    if old == new {
+        return;
+    }
+
+    if encoding == Encoding::Der {
+        println!("\nBinary file {:?} would change ({} -> {} bytes)", path, old.len(), new.len());
+        return;
+    }
+
+    let old_text = String::from_utf8_lossy(old);
+    let new_text = String::from_utf8_lossy(new);
+    let diff = similar::TextDiff::from_lines(old_text.as_ref(), new_text.as_ref());
+    println!(
+        "\n{}",
+        diff.unified_diff()
+            .header(&path.to_string_lossy(), &path.to_string_lossy())
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::X509;
+
+    #[test]
+    fn print_diff_does_not_panic_on_der_bytes() {
+        let old = [0xDE, 0xAD, 0xBE, 0xEF];
+        let new = [0xFE, 0xED, 0xFA, 0xCE];
+        print_diff(Path::new("cert.der"), &old, &new, Encoding::Der);
+    }
+
+    /// A minimal self-signed certificate, good enough to exercise logic that
+    /// never inspects its contents beyond its locator.
+    fn dummy_cert(path: &str) -> Cert {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec_key = EcKey::generate(&group).unwrap();
+        let key = PKey::from_ec_key(ec_key).unwrap();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.sign(&key, openssl::hash::MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        Cert {
+            common_name: String::new(),
+            sans: Vec::new(),
+            issuer: String::new(),
+            serial: String::new(),
+            key_algo: None,
+            not_before: None,
+            not_after: None,
+            cert,
+            locator: PEMLocator {
+                path: PathBuf::from(path),
+                start: 0,
+                end: 0,
+                kind: PEMKind::Cert,
+                encoding: Encoding::Pem,
+            },
+        }
+    }
+
+    #[test]
+    fn generate_pems_rejects_multiple_matches_in_one_file() {
+        let certs = vec![dummy_cert("fullchain.pem"), dummy_cert("fullchain.pem")];
+        let opts = WriteOptions {
+            dry_run: true,
+            backup: BackupPolicy::Disabled,
+        };
+
+        let failures = generate_pems(certs, None, &opts);
+
+        assert_eq!(failures.len(), 1);
+        let (path, err) = &failures[0];
+        assert_eq!(path, Path::new("fullchain.pem"));
+        assert!(err.to_string().contains("only supports"), "unexpected error: {}", err);
+    }
+
+    fn only_file_in(dir: &Path) -> PathBuf {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .next()
+            .expect("expected a backup file")
+    }
+
+    #[test]
+    fn backup_file_mirrors_source_directories_to_avoid_name_collisions() {
+        let tmp = std::env::temp_dir().join(format!("certreplace_backup_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&tmp);
+        fs::create_dir_all(tmp.join("site-a")).unwrap();
+        fs::create_dir_all(tmp.join("site-b")).unwrap();
+        let path_a = tmp.join("site-a").join("fullchain.pem");
+        let path_b = tmp.join("site-b").join("fullchain.pem");
+        fs::write(&path_a, b"cert-a").unwrap();
+        fs::write(&path_b, b"cert-b").unwrap();
+
+        let backup_dir = tmp.join("backups");
+        let policy = BackupPolicy::Dir(backup_dir.clone());
+        backup_file(&path_a, &policy).unwrap();
+        backup_file(&path_b, &policy).unwrap();
+
+        let dir_a = backup_dir.join(mirrored_path(&path_a).parent().unwrap());
+        let dir_b = backup_dir.join(mirrored_path(&path_b).parent().unwrap());
+        let backup_a = only_file_in(&dir_a);
+        let backup_b = only_file_in(&dir_b);
+
+        assert_ne!(backup_a, backup_b);
+        assert_eq!(fs::read(&backup_a).unwrap(), b"cert-a");
+        assert_eq!(fs::read(&backup_b).unwrap(), b"cert-b");
+
+        fs::remove_dir_all(&tmp).unwrap();
+    }
 }
@@ -0,0 +1,372 @@
+use chrono::{DateTime, Duration, Utc};
+use openssl::pkey::{PKey, Private};
+use openssl::x509::X509;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A single Subject Alternative Name entry parsed from a certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum San {
+    Dns(String),
+    Ip(String),
+    Email(String),
+}
+
+impl San {
+    /// The name or address this entry carries, regardless of kind.
+    pub fn value(&self) -> &str {
+        match self {
+            San::Dns(v) | San::Ip(v) | San::Email(v) => v,
+        }
+    }
+}
+
+/// The public key algorithm of a certificate, as selectable via
+/// `--key-algo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAlgo {
+    Rsa,
+    Ec,
+}
+
+impl FromStr for KeyAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rsa" => Ok(KeyAlgo::Rsa),
+            "ec" => Ok(KeyAlgo::Ec),
+            other => Err(format!(
+                "Unknown key algorithm '{}': expected 'rsa' or 'ec'",
+                other
+            )),
+        }
+    }
+}
+
+/// A parsed x509 certificate, together with the fields needed to match it
+/// against a `CertFilter` and the location it was parsed from.
+pub struct Cert {
+    pub common_name: String,
+    pub sans: Vec<San>,
+    pub issuer: String,
+    pub serial: String,
+    pub key_algo: Option<KeyAlgo>,
+    pub not_before: Option<DateTime<Utc>>,
+    pub not_after: Option<DateTime<Utc>>,
+    pub cert: X509,
+    pub locator: PEMLocator,
+}
+
+impl Cert {
+    /// Returns true if `name` matches this certificate's common name (unless
+    /// `san_only` is set) or any of its Subject Alternative Name entries.
+    pub fn matches_name(&self, name: &str, san_only: bool) -> bool {
+        if !san_only && self.common_name == name {
+            return true;
+        }
+        self.sans.iter().any(|san| san.value() == name)
+    }
+}
+
+/// A composable set of optional predicates used to select matching
+/// certificates. A certificate matches only if every predicate that is set
+/// matches; a filter with no predicates set matches everything.
+#[derive(Default, Clone)]
+pub struct CertFilter {
+    pub name: Option<String>,
+    pub san_only: bool,
+    pub expiring_within: Option<i64>,
+    pub issuer: Option<String>,
+    pub serial: Option<String>,
+    pub key_algo: Option<KeyAlgo>,
+}
+
+impl CertFilter {
+    /// Returns true if no predicates are set, i.e. this filter matches
+    /// every certificate.
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.expiring_within.is_none()
+            && self.issuer.is_none()
+            && self.serial.is_none()
+            && self.key_algo.is_none()
+    }
+
+    /// Returns true if `cert` satisfies every predicate that is set.
+    pub fn matches(&self, cert: &Cert) -> bool {
+        if let Some(name) = &self.name {
+            if !cert.matches_name(name, self.san_only) {
+                return false;
+            }
+        }
+        if let Some(days) = self.expiring_within {
+            // `Duration::days` and `DateTime + Duration` panic on overflow;
+            // `days` comes straight from user input via `--expiring-within`,
+            // so an out-of-range value must fail the match rather than take
+            // down the whole run.
+            let deadline = match Duration::try_days(days)
+                .and_then(|window| Utc::now().checked_add_signed(window))
+            {
+                Some(deadline) => deadline,
+                None => return false,
+            };
+            match cert.not_after {
+                Some(not_after) if not_after <= deadline => {}
+                _ => return false,
+            }
+        }
+        if let Some(issuer) = &self.issuer {
+            if !cert.issuer.contains(issuer.as_str()) {
+                return false;
+            }
+        }
+        if let Some(serial) = &self.serial {
+            if !cert.serial.eq_ignore_ascii_case(serial) {
+                return false;
+            }
+        }
+        if let Some(algo) = self.key_algo {
+            if cert.key_algo != Some(algo) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl fmt::Display for CertFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(name) = &self.name {
+            parts.push(format!(
+                "name '{}'{}",
+                name,
+                if self.san_only { " (SAN only)" } else { "" }
+            ));
+        }
+        if let Some(days) = self.expiring_within {
+            parts.push(format!("expiring within {} days", days));
+        }
+        if let Some(issuer) = &self.issuer {
+            parts.push(format!("issuer containing '{}'", issuer));
+        }
+        if let Some(serial) = &self.serial {
+            parts.push(format!("serial '{}'", serial));
+        }
+        if let Some(algo) = self.key_algo {
+            parts.push(format!("key algorithm {:?}", algo));
+        }
+        if parts.is_empty() {
+            write!(f, "all certificates")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+/// A parsed private key, together with the location it was parsed from.
+pub struct PrivKey {
+    pub key: PKey<Private>,
+    pub locator: PEMLocator,
+}
+
+/// A single PEM- or DER-encoded object read out of a file.
+pub enum PKIObject {
+    Cert(Cert),
+    PrivKey(PrivKey),
+}
+
+/// Which kind of PEM object a `PEMLocator` points at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PEMKind {
+    Cert,
+    PrivKey,
+}
+
+/// The byte-level encoding a `PEMLocator`'s target uses. A `Pem` locator
+/// spans an armored block inside a larger file; a `Der` locator always
+/// spans the whole file, since raw DER has no armor to search for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Pem,
+    Der,
+}
+
+/// Points at a PEM- or DER-encoded object inside a file by byte offset, so
+/// it can be spliced out and replaced without disturbing the rest of the
+/// file.
+#[derive(Debug, Clone)]
+pub struct PEMLocator {
+    pub path: PathBuf,
+    pub start: usize,
+    pub end: usize,
+    pub kind: PEMKind,
+    pub encoding: Encoding,
+}
+
+/// The top-level action requested by the user.
+pub enum Verb {
+    Find {
+        filter: CertFilter,
+    },
+    Replace {
+        filter: CertFilter,
+        cert: Box<Cert>,
+        privkey: Option<PrivKey>,
+    },
+    Generate {
+        filter: CertFilter,
+        ca_cert: Option<String>,
+        ca_key: Option<String>,
+    },
+}
+
+impl Verb {
+    /// The filter used to select matching certificates on disk.
+    pub fn filter(&self) -> &CertFilter {
+        match self {
+            Verb::Find { filter } => filter,
+            Verb::Replace { filter, .. } => filter,
+            Verb::Generate { filter, .. } => filter,
+        }
+    }
+}
+
+impl fmt::Display for Verb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verb::Find { filter } => write!(f, "Find certificates matching {}", filter),
+            Verb::Replace { filter, .. } => write!(f, "Replace certificates matching {}", filter),
+            Verb::Generate { filter, .. } => {
+                write!(f, "Generate replacements for certificates matching {}", filter)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid as OpensslNid;
+    use std::path::PathBuf;
+
+    fn dummy_cert(not_after: Option<DateTime<Utc>>) -> Cert {
+        let group = EcGroup::from_curve_name(OpensslNid::X9_62_PRIME256V1).unwrap();
+        let ec_key = EcKey::generate(&group).unwrap();
+        let key = PKey::from_ec_key(ec_key).unwrap();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .sign(&key, openssl::hash::MessageDigest::sha256())
+            .unwrap();
+        let cert = builder.build();
+
+        Cert {
+            common_name: String::new(),
+            sans: Vec::new(),
+            issuer: String::new(),
+            serial: String::new(),
+            key_algo: None,
+            not_before: None,
+            not_after,
+            cert,
+            locator: PEMLocator {
+                path: PathBuf::from("cert.pem"),
+                start: 0,
+                end: 0,
+                kind: PEMKind::Cert,
+                encoding: Encoding::Pem,
+            },
+        }
+    }
+
+    #[test]
+    fn matches_rejects_rather_than_panics_on_out_of_range_expiring_within() {
+        let filter = CertFilter {
+            expiring_within: Some(i64::MAX),
+            ..CertFilter::default()
+        };
+        let cert = dummy_cert(Some(Utc::now()));
+
+        assert!(!filter.matches(&cert));
+    }
+
+    #[test]
+    fn matches_name_falls_back_to_san_entries() {
+        let mut cert = dummy_cert(None);
+        cert.common_name = "example.com".to_string();
+        cert.sans = vec![San::Dns("www.example.com".to_string())];
+
+        assert!(cert.matches_name("www.example.com", false));
+        assert!(!cert.matches_name("other.example.com", false));
+    }
+
+    #[test]
+    fn matches_name_with_san_only_ignores_common_name() {
+        let mut cert = dummy_cert(None);
+        cert.common_name = "example.com".to_string();
+        cert.sans = vec![San::Dns("www.example.com".to_string())];
+
+        assert!(!cert.matches_name("example.com", true));
+        assert!(cert.matches_name("www.example.com", true));
+    }
+
+    #[test]
+    fn matches_checks_issuer_as_a_substring() {
+        let mut cert = dummy_cert(None);
+        cert.issuer = "CN=Intermediate CA,O=Example Inc".to_string();
+
+        let filter = CertFilter {
+            issuer: Some("Example Inc".to_string()),
+            ..CertFilter::default()
+        };
+        assert!(filter.matches(&cert));
+
+        let filter = CertFilter {
+            issuer: Some("Some Other CA".to_string()),
+            ..CertFilter::default()
+        };
+        assert!(!filter.matches(&cert));
+    }
+
+    #[test]
+    fn matches_checks_serial_case_insensitively() {
+        let mut cert = dummy_cert(None);
+        cert.serial = "AB12CD".to_string();
+
+        let filter = CertFilter {
+            serial: Some("ab12cd".to_string()),
+            ..CertFilter::default()
+        };
+        assert!(filter.matches(&cert));
+
+        let filter = CertFilter {
+            serial: Some("000000".to_string()),
+            ..CertFilter::default()
+        };
+        assert!(!filter.matches(&cert));
+    }
+
+    #[test]
+    fn matches_checks_key_algo() {
+        let mut cert = dummy_cert(None);
+        cert.key_algo = Some(KeyAlgo::Ec);
+
+        let filter = CertFilter {
+            key_algo: Some(KeyAlgo::Ec),
+            ..CertFilter::default()
+        };
+        assert!(filter.matches(&cert));
+
+        let filter = CertFilter {
+            key_algo: Some(KeyAlgo::Rsa),
+            ..CertFilter::default()
+        };
+        assert!(!filter.matches(&cert));
+    }
+}